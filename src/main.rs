@@ -2,81 +2,118 @@ use anyhow::{anyhow, Result};
 use clap;
 use clap::arg;
 use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn};
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::Read;
-use std::process::Command;
+use std::net::TcpStream;
+use std::process::{Command, Output};
 use std::{env, thread, time};
 use std::{io::Write, path::Path};
 use ureq;
 
+// derives a starting point in [0, modulo) from `name`, used to spread
+// per-instance resources (the ssh hostfwd port) across a range so
+// distinct names usually land on distinct values; callers still need to
+// probe from there, since a hash alone doesn't rule out collisions
+fn instance_slot(name: &str, modulo: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish() % modulo
+}
+
+// finds a TCP port that's currently free, starting at `base` and
+// scanning forward. Binding and immediately dropping the listener is
+// inherently racy (nothing stops another process from taking the port
+// before qemu binds it), but it's the same best-effort check the rest
+// of the toolchain relies on (e.g. qemu's own hostfwd) and is good
+// enough to avoid the common case of two named instances colliding.
+fn find_free_port(base: u16) -> Result<u16> {
+    for offset in 0..200u16 {
+        let port = base.wrapping_add(offset);
+        if std::net::TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return Ok(port);
+        }
+    }
+    Err(anyhow!("no free port found near {base}"))
+}
+
 #[derive(PartialEq, Default, Clone, Debug)]
 struct Commit {
     hash: String,
     message: String,
 }
 
-fn azure_create_group(group_name: &str) -> Result<()> {
-    let location = "northeurope";
+// runs `cmd`, logging the invocation and its exit status, and turns a
+// non-zero exit into an error carrying stderr
+fn run_command(cmd: &mut Command) -> Result<Output> {
+    debug!("running: {:?}", cmd);
+
+    let output = cmd.output()?;
 
-    let output = Command::new("az")
-        .arg("group")
-        .arg("create")
-        .arg("--location")
-        .arg(location)
-        .arg("--resource-group")
-        .arg(group_name)
-        .output()?;
+    debug!("{:?} exited with {}", cmd.get_program(), output.status);
 
     if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
+        let err = String::from_utf8_lossy(&output.stderr).to_string();
+        error!("{:?} failed: {}", cmd.get_program(), err);
         return Err(anyhow!(err));
     }
 
+    Ok(output)
+}
+
+fn azure_create_group(group_name: &str) -> Result<()> {
+    let location = "northeurope";
+
+    run_command(
+        Command::new("az")
+            .arg("group")
+            .arg("create")
+            .arg("--location")
+            .arg(location)
+            .arg("--resource-group")
+            .arg(group_name),
+    )?;
+
     Ok(())
 }
 
 fn azure_create_disk(group_name: &str, disk_name: &str, urn: &str) -> Result<()> {
-    let output = Command::new("az")
-        .arg("disk")
-        .arg("create")
-        .arg("--resource-group")
-        .arg(group_name)
-        .arg("--name")
-        .arg(disk_name)
-        .arg("--hyper-v-generation")
-        .arg("V2")
-        .arg("--image-reference")
-        .arg(urn)
-        .output()?;
-
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(
+        Command::new("az")
+            .arg("disk")
+            .arg("create")
+            .arg("--resource-group")
+            .arg(group_name)
+            .arg("--name")
+            .arg(disk_name)
+            .arg("--hyper-v-generation")
+            .arg("V2")
+            .arg("--image-reference")
+            .arg(urn),
+    )?;
 
     Ok(())
 }
 
 fn azure_export_disk(group_name: &str, disk_name: &str) -> Result<String> {
-    let output = Command::new("az")
-        .arg("disk")
-        .arg("grant-access")
-        .arg("--resource-group")
-        .arg(group_name)
-        .arg("--name")
-        .arg(disk_name)
-        .arg("--duration")
-        // 24h
-        .arg("86400")
-        .arg("--query")
-        .arg("accessSas")
-        .output()?;
+    let output = run_command(
+        Command::new("az")
+            .arg("disk")
+            .arg("grant-access")
+            .arg("--resource-group")
+            .arg(group_name)
+            .arg("--name")
+            .arg(disk_name)
+            .arg("--duration")
+            // 24h
+            .arg("86400")
+            .arg("--query")
+            .arg("accessSas"),
+    )?;
 
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
     let url_with_quotes = String::from_utf8(output.stdout)?;
     let url = url_with_quotes.trim().trim_matches('"');
 
@@ -139,7 +176,7 @@ fn azure_download_disk(url: &str, filename: &str) -> Result<()> {
         match io::copy(&mut reader, &mut file) {
             Ok(_) => break,
             Err(err) => {
-                println!("{:?}", err);
+                warn!("download retry {}/10 after error: {:?}", retries + 1, err);
             }
         };
 
@@ -151,19 +188,15 @@ fn azure_download_disk(url: &str, filename: &str) -> Result<()> {
 
 fn azure_delete_group(group_name: &str) -> Result<()> {
     // az group delete --no-wait -y -g
-    let output = Command::new("az")
-        .arg("group")
-        .arg("delete")
-        .arg("--resource-group")
-        .arg(group_name)
-        .arg("--no-wait")
-        .arg("--yes")
-        .output()?;
-
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(
+        Command::new("az")
+            .arg("group")
+            .arg("delete")
+            .arg("--resource-group")
+            .arg(group_name)
+            .arg("--no-wait")
+            .arg("--yes"),
+    )?;
 
     Ok(())
 }
@@ -181,7 +214,7 @@ fn download_image(suite: &str, file: &str, force: bool) -> Result<()> {
     azure_create_disk(group_name, disk_name, &urn)?;
     let url = azure_export_disk(group_name, disk_name)?;
 
-    println!("downloading disk, may take a while...");
+    info!("downloading disk, may take a while...");
     azure_download_disk(&url, file)?;
 
     azure_delete_group(group_name)?;
@@ -199,17 +232,13 @@ fn customize_cloudinit(mountpoint: &str) -> Result<()> {
 
 fn attach_nbd_device(nbd_device: &str, image: &str) -> Result<()> {
     // attach the image to a nbd chardev
-    let output = Command::new("qemu-nbd")
-        .arg("--format")
-        .arg("raw")
-        .arg(format!("--connect={nbd_device}"))
-        .arg(image)
-        .output()?;
-
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(
+        Command::new("qemu-nbd")
+            .arg("--format")
+            .arg("raw")
+            .arg(format!("--connect={nbd_device}"))
+            .arg(image),
+    )?;
 
     // nbd is async so we want to make sure the device is attached
     // before returning otherwise mount will fail
@@ -230,78 +259,176 @@ fn attach_nbd_device(nbd_device: &str, image: &str) -> Result<()> {
 
 fn customize_rootfs(mountpoint: &str) -> Result<()> {
     // disable walinuxagent
-    let output = Command::new("chroot")
-        .arg(&mountpoint)
-        .arg("systemctl")
-        .arg("mask")
-        .arg("walinuxagent")
-        .output()?;
+    run_command(
+        Command::new("chroot")
+            .arg(mountpoint)
+            .arg("systemctl")
+            .arg("mask")
+            .arg("walinuxagent"),
+    )?;
 
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
+    customize_cloudinit(mountpoint)?;
+
+    Ok(())
+}
+
+fn detect_image_format(image: &str) -> Result<String> {
+    let output = run_command(Command::new("qemu-img").arg("info").arg(image))?;
+
+    let stdout = String::from_utf8(output.stdout)?;
+    for line in stdout.lines() {
+        if let Some(format) = line.trim().strip_prefix("file format: ") {
+            return Ok(format.trim().to_string());
+        }
     }
 
-    customize_cloudinit(&mountpoint)?;
+    Err(anyhow!(format!(
+        "could not determine image format of {image}"
+    )))
+}
 
-    Ok(())
+fn convert_image_to_raw(image: &str, format: &str, destination: &str) -> Result<String> {
+    // run_command captures stdout and never surfaces it (only stderr, on
+    // error), so -p's progress output would never reach the terminal; skip
+    // it here
+    run_command(
+        Command::new("qemu-img")
+            .arg("convert")
+            .arg("-f")
+            .arg(format)
+            .arg("-O")
+            .arg("raw")
+            .arg(image)
+            .arg(destination),
+    )?;
+
+    Ok(destination.to_string())
 }
 
-fn customize_image(image: &str) -> Result<()> {
-    // make sure the nbd module is loaded
-    let output = Command::new("modprobe").arg("nbd").output()?;
+// converts image to raw if needed, returning a path to a raw image ready
+// for NBD attach or qemu -drive if=virtio,format=raw. `destination` is
+// only used (and created) when a conversion is actually needed.
+fn ensure_raw_image(image: &str, destination: &str) -> Result<String> {
+    let format = detect_image_format(image)?;
 
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
+    if format == "raw" {
+        return Ok(image.to_string());
+    }
+
+    info!("Converting {image} from {format} to raw...");
+    convert_image_to_raw(image, &format, destination)
+}
+
+// picks the first /dev/nbdN (N in 0..16, matching the kernel module's
+// default nbds_max) that doesn't already have an image attached, so
+// concurrent `image customize`/`vm start` runs don't fight over the same
+// device. A device's "size" sysfs attribute reads back 0 while it's
+// disconnected, so that's what we probe instead of tracking state
+// ourselves.
+fn find_free_nbd_device() -> Result<String> {
+    for minor in 0..16 {
+        let size = fs::read_to_string(format!("/sys/class/block/nbd{minor}/size"))
+            .unwrap_or_else(|_| "0".to_string());
+        if size.trim() == "0" {
+            return Ok(format!("/dev/nbd{minor}"));
+        }
     }
+    Err(anyhow!("no free /dev/nbdN device found"))
+}
+
+fn customize_image(image: &str) -> Result<()> {
+    // make sure the nbd module is loaded
+    run_command(Command::new("modprobe").arg("nbd"))?;
 
-    let nbd_device = "/dev/nbd0";
+    let nbd_device = find_free_nbd_device()?;
 
-    attach_nbd_device(nbd_device, image)?;
+    attach_nbd_device(&nbd_device, image)?;
 
     // mount the rootfs
-    let mountpoint = format!("{}/mountpoint", env::temp_dir().display());
+    // keyed by pid so concurrent customize runs never share a mountpoint
+    let mountpoint = format!(
+        "{}/mountpoint-{}",
+        env::temp_dir().display(),
+        std::process::id()
+    );
     fs::create_dir_all(&mountpoint)?;
 
-    let output = Command::new("mount")
-        .arg(format!("{nbd_device}p1"))
-        .arg(&mountpoint)
-        .output()?;
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(Command::new("mount").arg(format!("{nbd_device}p1")).arg(&mountpoint))?;
 
     customize_rootfs(&mountpoint)?;
 
     // umounting
-    let output = Command::new("umount").arg(&mountpoint).output()?;
-
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(Command::new("umount").arg(&mountpoint))?;
 
     // removing mountpoint
     fs::remove_dir(&mountpoint)?;
 
     // disconnecting nbd
-    let output = Command::new("qemu-nbd")
-        .arg("--disconnect")
-        .arg(nbd_device)
-        .output()?;
-
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(Command::new("qemu-nbd").arg("--disconnect").arg(&nbd_device))?;
 
     Ok(())
 }
 
-fn create_cloudinit_drive(key_id: &str) -> Result<String> {
-    let drive = String::from("seed.img");
+// Per-instance state directory and the file paths derived from it, so that
+// multiple named VMs/vTPMs can run side by side without clobbering each
+// other's pidfiles, sockets and seed images.
+struct InstancePaths {
+    vtpm_directory: String,
+    vtpm_pid_file: String,
+    vtpm_socket: String,
+    qemu_pid_file: String,
+    qmp_socket: String,
+    cloudinit_drive: String,
+    ovmf_vars: String,
+    converted_image: String,
+    ssh_port: u16,
+}
+
+impl InstancePaths {
+    fn new(name: &str) -> Result<Self> {
+        let state_dir = format!("{}/cvm-tools/{name}", env::temp_dir().display());
+        fs::create_dir_all(&state_dir)?;
+
+        let vtpm_directory = format!("{state_dir}/vtpm");
+
+        // `vm start` picks the port once and `vm verify` must agree on
+        // the same one afterwards, so it's persisted next to the
+        // rest of the instance's state instead of re-derived on every call
+        // (re-probing later would just find the port busy with our own
+        // qemu and skip past it).
+        let ssh_port_file = format!("{state_dir}/ssh_port");
+        let ssh_port = match fs::read_to_string(&ssh_port_file) {
+            Ok(existing) => existing
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid ssh port in {ssh_port_file}"))?,
+            Err(_) => {
+                let base = (2200 + instance_slot(name, 800)) as u16;
+                let port = find_free_port(base)?;
+                fs::write(&ssh_port_file, port.to_string())?;
+                port
+            }
+        };
+
+        Ok(InstancePaths {
+            vtpm_pid_file: format!("{state_dir}/vtpm_pid"),
+            vtpm_socket: format!("{vtpm_directory}/swtpm-sock"),
+            vtpm_directory,
+            qemu_pid_file: format!("{state_dir}/qemu_pid"),
+            qmp_socket: format!("{state_dir}/qemu-qmp.sock"),
+            cloudinit_drive: format!("{state_dir}/seed.img"),
+            ovmf_vars: format!("{state_dir}/OVMF_VARS.ms.fd"),
+            // reused across runs of the same named instance instead of a
+            // fresh {image}.raw next to the source every time, so repeated
+            // `vm start` doesn't accumulate multi-GB raw files
+            converted_image: format!("{state_dir}/image.raw"),
+            ssh_port,
+        })
+    }
+}
+
+fn create_cloudinit_drive(key_id: &str, drive: &str) -> Result<String> {
+    let drive = String::from(drive);
 
     let user_data = format!("{}/user_data.yaml", env::temp_dir().display());
     let mut file = match fs::File::create(&user_data) {
@@ -313,30 +440,70 @@ fn create_cloudinit_drive(key_id: &str) -> Result<String> {
     writeln!(&mut file, "ssh_import_id:")?;
     writeln!(&mut file, "  - {}", key_id)?;
 
-    let output = Command::new("cloud-localds")
-        .arg(&drive)
-        .arg(&user_data)
-        .output()?;
-
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(Command::new("cloud-localds").arg(&drive).arg(&user_data))?;
 
     Ok(drive)
 }
 
-fn copy_ovmf_vars() -> Result<String> {
-    let copy_path = String::from("/tmp/OVMF_VARS.ms.fd");
-    fs::copy("/usr/share/OVMF/OVMF_VARS_4M.ms.fd", &copy_path)?;
+fn copy_ovmf_vars(copy_path: &str) -> Result<String> {
+    fs::copy("/usr/share/OVMF/OVMF_VARS_4M.ms.fd", copy_path)?;
 
-    Ok(copy_path)
+    Ok(copy_path.to_string())
 }
 
-fn start_vm(image: &str, cloudinit_drive: &str, vtpm_socket: &str) -> Result<()> {
+fn parse_memory_size(raw: &str) -> Result<u64> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Err(anyhow!("empty memory size"));
+    }
+
+    let (digits, multiplier) = match raw.chars().last().unwrap().to_ascii_uppercase() {
+        'G' => (&raw[..raw.len() - 1], 1024),
+        'M' => (&raw[..raw.len() - 1], 1),
+        _ => (raw, 1),
+    };
+
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| anyhow!(format!("invalid memory size: {raw}")))?;
+
+    Ok(value * multiplier)
+}
+
+struct VmConfig<'a> {
+    image: &'a str,
+    cloudinit_drive: &'a str,
+    vtpm_socket: &'a str,
+    cpus: u32,
+    memory_mb: u64,
+    extra_disks: &'a [String],
+    pid_file: &'a str,
+    qmp_socket: &'a str,
+    ovmf_vars_path: &'a str,
+    num_queues: u32,
+    queue_size: u32,
+    ssh_port: u16,
+}
+
+fn start_vm(config: &VmConfig) -> Result<()> {
+    let VmConfig {
+        image,
+        cloudinit_drive,
+        vtpm_socket,
+        cpus,
+        memory_mb,
+        extra_disks,
+        pid_file,
+        qmp_socket,
+        ovmf_vars_path,
+        num_queues,
+        queue_size,
+        ssh_port,
+    } = *config;
+
     let mut cmd = Command::new("qemu-system-x86_64");
 
-    let ovmf_vars = match copy_ovmf_vars() {
+    let ovmf_vars = match copy_ovmf_vars(ovmf_vars_path) {
         Ok(path) => path,
         Err(err) => {
             return Err(anyhow!(format!("failed to copy OVMF: {:?}", err)));
@@ -346,21 +513,23 @@ fn start_vm(image: &str, cloudinit_drive: &str, vtpm_socket: &str) -> Result<()>
     // basic VM config
     cmd.arg("--cpu")
         .arg("host")
+        .arg("-smp")
+        .arg(cpus.to_string())
         .arg("-machine")
         .arg("type=q35,accel=kvm")
         .arg("-m")
-        .arg("2048")
+        .arg(memory_mb.to_string())
         // config for qemu process
         .arg("-daemonize")
         .arg("-pidfile")
-        .arg("/tmp/qemu_pid")
+        .arg(pid_file)
         .arg("-qmp")
-        .arg("unix:/tmp/qemu-qmp.sock,server=on,wait=off")
+        .arg(format!("unix:{qmp_socket},server=on,wait=off"))
         // Run the VM without modifying attached disks
         .arg("-snapshot")
         // Configuring networking
         .arg("-netdev")
-        .arg("id=net00,type=user,hostfwd=tcp::2222-:22")
+        .arg(format!("id=net00,type=user,hostfwd=tcp::{ssh_port}-:22"))
         .arg("-device")
         .arg("virtio-net-pci,netdev=net00")
         // tpm
@@ -370,9 +539,16 @@ fn start_vm(image: &str, cloudinit_drive: &str, vtpm_socket: &str) -> Result<()>
         .arg("emulator,id=tpm0,chardev=chrtpm")
         .arg("-device")
         .arg("tpm-tis,tpmdev=tpm0")
-        // Attaching image drive
+        // Attaching image drive as a virtio-blk device with a tunable
+        // number of queues/queue depth, for parallel block I/O on
+        // multi-vCPU guests (matters most for FDE images, where every
+        // block goes through dm-crypt)
         .arg("-drive")
-        .arg(format!("if=virtio,format=raw,file={image}"))
+        .arg(format!("if=none,format=raw,file={image},id=drive0"))
+        .arg("-device")
+        .arg(format!(
+            "virtio-blk-pci,drive=drive0,num-queues={num_queues},queue-size={queue_size}"
+        ))
         // Attaching cloud-init drive (for NoCloud datasource)
         .arg("-drive")
         .arg(format!("if=virtio,format=raw,file={cloudinit_drive}"))
@@ -382,21 +558,125 @@ fn start_vm(image: &str, cloudinit_drive: &str, vtpm_socket: &str) -> Result<()>
         .arg("-drive")
         .arg(format!("if=pflash,format=raw,unit=1,file={ovmf_vars}"));
 
-    // Running the command
-    let output = match cmd.output() {
-        Ok(output) => output,
-        Err(err) => {
-            return Err(anyhow!(format!("failed to run qemu: {:?}", err)));
-        }
-    };
-
-    if !output.status.success() {
-        return Err(anyhow!(String::from_utf8(output.stderr)?));
+    // Attaching any extra virtio-blk data disks requested on the command
+    // line. -snapshot above applies to every drive by default, which would
+    // silently discard writes to these on shutdown; opt them back out so
+    // data written to them actually persists.
+    for disk in extra_disks {
+        cmd.arg("-drive")
+            .arg(format!("if=virtio,format=raw,file={disk},snapshot=off"));
     }
 
+    run_command(&mut cmd)?;
+
     Ok(())
 }
 
+struct VerificationCheck {
+    name: String,
+    passed: bool,
+    output: String,
+}
+
+// `key_id` is the ssh_import_id identity create_cloudinit_drive provisions
+// into the guest (e.g. "gh:gjolly"); we don't hold a local copy of that key
+// to authenticate with directly, so this relies on the operator's
+// ssh-agent already carrying the matching private key. Checking for any
+// agent identity at all up front turns an empty agent into a clear
+// error instead of the opaque "Username/PublicKey combination invalid"
+// ssh2 would otherwise return from userauth_agent.
+fn connect_and_authenticate(addr: &str, user: &str, key_id: &str) -> Result<ssh2::Session> {
+    let stream = TcpStream::connect(addr)?;
+
+    let mut session = ssh2::Session::new()?;
+    session.set_tcp_stream(stream);
+    session.handshake()?;
+
+    let mut agent = session.agent()?;
+    agent.connect()?;
+    agent.list_identities()?;
+    if agent.identities()?.is_empty() {
+        return Err(anyhow!(
+            "ssh-agent has no identities loaded; it must hold the private key for the `{key_id}` identity imported by create_cloudinit_drive"
+        ));
+    }
+
+    session.userauth_agent(user)?;
+
+    if !session.authenticated() {
+        return Err(anyhow!(
+            "ssh authentication failed; does ssh-agent hold the key for `{key_id}`?"
+        ));
+    }
+
+    Ok(session)
+}
+
+// retries the full connect+handshake+auth sequence until it succeeds or
+// the deadline passes. The hostfwd listener accepts TCP connections as
+// soon as start_vm launches qemu, well before the guest sshd is up, so
+// a bare TCP connect is not a useful readiness signal on its own.
+fn wait_for_ssh(
+    addr: &str,
+    user: &str,
+    key_id: &str,
+    timeout: time::Duration,
+) -> Result<ssh2::Session> {
+    let deadline = time::Instant::now() + timeout;
+    let retry_delay = time::Duration::from_secs(2);
+    let mut last_err = anyhow!("no attempt made");
+
+    while time::Instant::now() < deadline {
+        match connect_and_authenticate(addr, user, key_id) {
+            Ok(session) => return Ok(session),
+            Err(err) => {
+                last_err = err;
+                thread::sleep(retry_delay);
+            }
+        }
+    }
+
+    Err(anyhow!(format!(
+        "timed out waiting for ssh on {addr}: {last_err}"
+    )))
+}
+
+fn run_remote_check(
+    session: &ssh2::Session,
+    name: &str,
+    command: &str,
+) -> Result<VerificationCheck> {
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+
+    Ok(VerificationCheck {
+        name: name.to_string(),
+        passed: channel.exit_status()? == 0,
+        output,
+    })
+}
+
+fn verify_vm(user: &str, key_id: &str, ssh_port: u16) -> Result<Vec<VerificationCheck>> {
+    // start_vm forwards this same per-instance host port to guest port 22.
+    let addr = format!("127.0.0.1:{ssh_port}");
+    let session = wait_for_ssh(&addr, user, key_id, time::Duration::from_secs(120))?;
+
+    let checks = vec![
+        run_remote_check(&session, "vTPM device present", "test -e /sys/class/tpm/tpm0")?,
+        run_remote_check(
+            &session,
+            "root filesystem is LUKS/dm-crypt",
+            "lsblk -no TYPE \"$(findmnt -n -o SOURCE /)\" | grep -q crypt",
+        )?,
+    ];
+
+    Ok(checks)
+}
+
 fn start_vtpm(state_directory: &str, socket: &str, pid_file: &str, server: bool) -> Result<()> {
     fs::create_dir_all(state_directory)?;
 
@@ -418,11 +698,7 @@ fn start_vtpm(state_directory: &str, socket: &str, pid_file: &str, server: bool)
             .arg(format!("type=unixio,path={socket}"));
     }
 
-    let output = cmd.output()?;
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(&mut cmd)?;
 
     Ok(())
 }
@@ -443,21 +719,20 @@ fn status_vtpm(state_directory: &str, pid_file: &str) -> String {
 fn kill_process(pid_file: &str) -> Result<()> {
     let pid = fs::read_to_string(pid_file)?;
 
-    let output = Command::new("kill").arg(pid.trim()).output()?;
-
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
-    }
+    run_command(Command::new("kill").arg(pid.trim()))?;
 
     Ok(())
 }
 
-fn kill_vm() -> Result<()> {
-    let pid_file = "/tmp/qemu_pid";
+fn kill_vm(pid_file: &str, converted_image: &str) -> Result<()> {
     kill_process(pid_file)?;
     fs::remove_file(pid_file)?;
 
+    // clean up the raw conversion left behind by ensure_raw_image, if any
+    if Path::new(converted_image).exists() {
+        fs::remove_file(converted_image)?;
+    }
+
     Ok(())
 }
 
@@ -468,32 +743,68 @@ fn destroy_vtpm(directory: &str) -> Result<()> {
 }
 
 fn generate_srk(socket: &str) -> Result<()> {
-    let output = Command::new("tpm2_createprimary")
-        .arg("-T")
-        .arg(format!("swtpm:path={socket}"))
-        .arg("-c")
-        .arg("srk.ctx")
-        .output()?;
+    run_command(
+        Command::new("tpm2_createprimary")
+            .arg("-T")
+            .arg(format!("swtpm:path={socket}"))
+            .arg("-c")
+            .arg("srk.ctx"),
+    )?;
+
+    run_command(
+        Command::new("tpm2_readpublic")
+            .arg("-T")
+            .arg(format!("swtpm:path={socket}"))
+            .arg("-c")
+            .arg("srk.ctx")
+            .arg("-o")
+            .arg("srk.pub"),
+    )?;
 
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
+    Ok(())
+}
+
+// A minimal logger that prefixes each line with the time elapsed since
+// startup and the log level, so multi-step flows (az/qemu-nbd/swtpm/tpm2)
+// can be traced without guessing where time was spent.
+struct Logger {
+    start: time::Instant,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
     }
 
-    let output = Command::new("tpm2_readpublic")
-        .arg("-T")
-        .arg(format!("swtpm:path={socket}"))
-        .arg("-c")
-        .arg("srk.ctx")
-        .arg("-o")
-        .arg("srk.pub")
-        .output()?;
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
 
-    if !output.status.success() {
-        let err = String::from_utf8(output.stderr)?;
-        return Err(anyhow!(err));
+        eprintln!(
+            "[{:>8.3}s] {:<5} {}",
+            self.start.elapsed().as_secs_f64(),
+            record.level(),
+            record.args()
+        );
     }
 
+    fn flush(&self) {}
+}
+
+fn init_logger(verbosity: u8) -> Result<()> {
+    let level = match verbosity {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+
+    log::set_boxed_logger(Box::new(Logger {
+        start: time::Instant::now(),
+    }))
+    .map_err(|err| anyhow!(format!("failed to initialize logger: {err}")))?;
+    log::set_max_level(level);
+
     Ok(())
 }
 
@@ -501,6 +812,11 @@ fn cli() -> clap::Command {
     clap::Command::new("cvm-tools")
         .about("A tool for managing vTPM backed FDE images and VMs.")
         .subcommand_required(true)
+        .arg(
+            arg!(-v --verbose "Increase logging verbosity (-v debug, -vv trace)")
+                .action(clap::ArgAction::Count)
+                .global(true),
+        )
         .subcommand(
             clap::Command::new("image")
                 .about("Manage cloud images")
@@ -516,12 +832,22 @@ fn cli() -> clap::Command {
                     clap::Command::new("customize").arg(
                         arg!([IMAGE])
                     ),
+                )
+                .subcommand(
+                    clap::Command::new("convert")
+                        .about("Convert a qcow2 (or other) image to raw")
+                        .arg(arg!([IMAGE])),
                 ),
         )
         .subcommand(
             clap::Command::new("tpm")
                 .about("Manage vTPM")
                 .subcommand_required(true)
+                .arg(
+                    arg!(--name <ID>)
+                        .default_value("default")
+                        .global(true),
+                )
                 .subcommand(clap::Command::new("start"))
                 .subcommand(clap::Command::new("setup"))
                 .subcommand(clap::Command::new("kill"))
@@ -532,12 +858,44 @@ fn cli() -> clap::Command {
             clap::Command::new("vm")
                 .about("Manage VMs")
                 .subcommand_required(true)
+                .arg(
+                    arg!(--name <ID>)
+                        .default_value("default")
+                        .global(true),
+                )
                 .subcommand(
-                    clap::Command::new("start").arg(
-                        arg!([IMAGE])
-                    ),
+                    clap::Command::new("start")
+                        .arg(arg!([IMAGE]))
+                        .arg(
+                            arg!(--cpus <N>)
+                                .default_value("1")
+                                .value_parser(clap::value_parser!(u32)),
+                        )
+                        .arg(arg!(--memory <SIZE>).default_value("2048M"))
+                        .arg(
+                            arg!(--disk <PATH>)
+                                .required(false)
+                                .action(clap::ArgAction::Append),
+                        )
+                        .arg(
+                            clap::Arg::new("num-queues")
+                                .long("num-queues")
+                                .value_name("N")
+                                .default_value("1")
+                                .value_parser(clap::value_parser!(u32)),
+                        )
+                        .arg(
+                            clap::Arg::new("queue-size")
+                                .long("queue-size")
+                                .value_name("N")
+                                .default_value("128")
+                                .value_parser(clap::value_parser!(u32)),
+                        ),
                 )
-                .subcommand(clap::Command::new("kill")),
+                .subcommand(clap::Command::new("kill"))
+                .subcommand(
+                    clap::Command::new("verify").arg(arg!(--user <USER>).default_value("ubuntu")),
+                ),
         )
 }
 
@@ -555,11 +913,9 @@ fn check_dependencies(dependencies: Vec<&str>) -> Result<()> {
 fn main() -> Result<()> {
     let matches = cli().get_matches();
 
-    let key_id = "gh:gjolly";
+    init_logger(matches.get_count("verbose"))?;
 
-    let tpm_pid_file = "/tmp/vtpm_pid";
-    let tpm_directory = "/tmp/vtpm";
-    let tpm_socket = String::from(format!("{tpm_directory}/swtpm-sock"));
+    let key_id = "gh:gjolly";
 
     match matches.subcommand() {
         Some(("image", sub_matches)) => match sub_matches.subcommand() {
@@ -570,65 +926,108 @@ fn main() -> Result<()> {
 
                 let image_file = format!("{suite}.img");
 
-                println!("Downloading image file from azure: {}", &image_file);
+                info!("Downloading image file from azure: {}", &image_file);
                 download_image(suite, &image_file, false)?;
             }
             Some(("customize", ssub_matches)) => {
-                check_dependencies(vec!["qemu-nbd"])?;
+                check_dependencies(vec!["qemu-nbd", "qemu-img"])?;
+                let image = ssub_matches.get_one::<String>("IMAGE").expect("required");
+
+                let raw_image = ensure_raw_image(image, &format!("{image}.raw"))?;
+
+                info!("Customizing image: {}", &raw_image);
+                customize_image(&raw_image)?;
+            }
+            Some(("convert", ssub_matches)) => {
+                check_dependencies(vec!["qemu-img"])?;
                 let image = ssub_matches.get_one::<String>("IMAGE").expect("required");
 
-                println!("Customizing image: {}", &image);
-                customize_image(&image)?;
+                let raw_image = ensure_raw_image(image, &format!("{image}.raw"))?;
+                info!("Raw image available at: {}", &raw_image);
             }
             _ => {
-                println!("not implemented");
+                error!("not implemented");
             }
         },
-        Some(("tpm", sub_matches)) => match sub_matches.subcommand() {
-            Some(("start", _)) => {
-                check_dependencies(vec!["swtpm"])?;
+        Some(("tpm", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("name").expect("has default");
+            let paths = InstancePaths::new(name)?;
 
-                println!("Staring vTPM");
-                start_vtpm(&tpm_directory, &tpm_socket, &tpm_pid_file, false)?;
-            }
-            Some(("setup", _)) => {
-                check_dependencies(vec!["swtpm", "tpm2"])?;
+            match sub_matches.subcommand() {
+                Some(("start", _)) => {
+                    check_dependencies(vec!["swtpm"])?;
+
+                    info!("Staring vTPM");
+                    start_vtpm(
+                        &paths.vtpm_directory,
+                        &paths.vtpm_socket,
+                        &paths.vtpm_pid_file,
+                        false,
+                    )?;
+                }
+                Some(("setup", _)) => {
+                    check_dependencies(vec!["swtpm", "tpm2"])?;
 
-                println!("Creating SRK");
-                start_vtpm(&tpm_directory, &tpm_socket, &tpm_pid_file, true)?;
+                    info!("Creating SRK");
+                    start_vtpm(
+                        &paths.vtpm_directory,
+                        &paths.vtpm_socket,
+                        &paths.vtpm_pid_file,
+                        true,
+                    )?;
 
-                // TODO: verify that TPM socket exists
-                generate_srk(&tpm_socket)?;
+                    // TODO: verify that TPM socket exists
+                    generate_srk(&paths.vtpm_socket)?;
 
-                kill_process(&tpm_pid_file)?;
-            }
-            Some(("kill", _)) => {
-                println!("Stopping TPM");
-                // TODO: verify that pid file exists
-                kill_process(&tpm_pid_file)?;
-            }
-            Some(("destroy", _)) => {
-                println!("Destroying vTPM state");
-                // TODO: verify that pid file exists
-                let _ = kill_process(&tpm_pid_file);
-                destroy_vtpm(&tpm_directory)?;
-            }
-            Some(("status", _)) => {
-                println!("{}", status_vtpm(&tpm_directory, &tpm_pid_file));
-            }
-            _ => {
-                println!("not implemented");
+                    kill_process(&paths.vtpm_pid_file)?;
+                }
+                Some(("kill", _)) => {
+                    info!("Stopping TPM");
+                    // TODO: verify that pid file exists
+                    kill_process(&paths.vtpm_pid_file)?;
+                }
+                Some(("destroy", _)) => {
+                    info!("Destroying vTPM state");
+                    // TODO: verify that pid file exists
+                    let _ = kill_process(&paths.vtpm_pid_file);
+                    destroy_vtpm(&paths.vtpm_directory)?;
+                }
+                Some(("status", _)) => {
+                    info!("{}", status_vtpm(&paths.vtpm_directory, &paths.vtpm_pid_file));
+                }
+                _ => {
+                    error!("not implemented");
+                }
             }
-        },
+        }
         Some(("vm", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("name").expect("has default");
+            let paths = InstancePaths::new(name)?;
+
             match sub_matches.subcommand() {
                 Some(("start", ssub_matches)) => {
-                    check_dependencies(vec!["qemu-system-x86_64", "cloud-localds"])?;
-
-                    let image = ssub_matches.get_one::<String>("image").expect("required");
-
-                    println!("Creating cloud-init config drive");
-                    let cloudinit_drive = match create_cloudinit_drive(key_id) {
+                    check_dependencies(vec!["qemu-system-x86_64", "cloud-localds", "qemu-img"])?;
+
+                    let image = ssub_matches.get_one::<String>("IMAGE").expect("required");
+                    let image = ensure_raw_image(image, &paths.converted_image)?;
+                    let cpus = *ssub_matches.get_one::<u32>("cpus").expect("has default");
+                    let memory = ssub_matches
+                        .get_one::<String>("memory")
+                        .expect("has default");
+                    let memory_mb = parse_memory_size(memory)?;
+                    let extra_disks: Vec<String> = ssub_matches
+                        .get_many::<String>("disk")
+                        .map(|values| values.cloned().collect())
+                        .unwrap_or_default();
+                    let num_queues = *ssub_matches
+                        .get_one::<u32>("num-queues")
+                        .expect("has default");
+                    let queue_size = *ssub_matches
+                        .get_one::<u32>("queue-size")
+                        .expect("has default");
+
+                    info!("Creating cloud-init config drive");
+                    let cloudinit_drive = match create_cloudinit_drive(key_id, &paths.cloudinit_drive) {
                         Ok(path) => path,
                         Err(err) => {
                             return Err(anyhow!(format!(
@@ -638,23 +1037,59 @@ fn main() -> Result<()> {
                         }
                     };
 
-                    println!("Starting VM: {}", &image);
+                    info!("Starting VM: {}", &image);
                     // TODO: verify that TPM socket exists
-                    start_vm(&image, &cloudinit_drive, &tpm_socket)?;
-                    println!("connect to QMP with:");
-                    println!("    qmp-shell /tmp/qemu-qmp.sock");
+                    start_vm(&VmConfig {
+                        image: &image,
+                        cloudinit_drive: &cloudinit_drive,
+                        vtpm_socket: &paths.vtpm_socket,
+                        cpus,
+                        memory_mb,
+                        extra_disks: &extra_disks,
+                        pid_file: &paths.qemu_pid_file,
+                        qmp_socket: &paths.qmp_socket,
+                        ovmf_vars_path: &paths.ovmf_vars,
+                        num_queues,
+                        queue_size,
+                        ssh_port: paths.ssh_port,
+                    })?;
+                    info!("connect to QMP with:");
+                    info!("    qmp-shell {}", &paths.qmp_socket);
                 }
                 Some(("kill", _)) => {
                     // TODO: verify that pid file exists
-                    kill_vm()?;
+                    kill_vm(&paths.qemu_pid_file, &paths.converted_image)?;
+                }
+                Some(("verify", ssub_matches)) => {
+                    let user = ssub_matches.get_one::<String>("user").expect("has default");
+
+                    info!("Verifying VM over SSH (127.0.0.1:{})...", paths.ssh_port);
+                    let checks = verify_vm(user, key_id, paths.ssh_port)?;
+
+                    let mut all_passed = true;
+                    for check in &checks {
+                        if check.passed {
+                            info!("  [ok]   {}", check.name);
+                        } else {
+                            all_passed = false;
+                            error!("  [FAIL] {}", check.name);
+                            error!("{}", check.output);
+                        }
+                    }
+
+                    if !all_passed {
+                        return Err(anyhow!("VM verification failed"));
+                    }
+
+                    info!("VM verification passed");
                 }
                 _ => {
-                    println!("not implemented");
+                    error!("not implemented");
                 }
             }
         }
         _ => {
-            println!("not implemented");
+            error!("not implemented");
         }
     }
 